@@ -1,6 +1,10 @@
 use mac_address::MacAddress;
 use std::net::Ipv6Addr;
 
+pub mod ndisc;
+pub mod pcap;
+pub mod simulate;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Pos {
     pub x: u16,
@@ -43,6 +47,40 @@ impl EthernetInfo {
     }
 }
 
+/// Which checksums the caller has told us are already handled elsewhere,
+/// mirroring smoltcp's `ChecksumCapabilities`. Threaded through the packet
+/// builders so the compute-vs-offload decision is made once per run rather
+/// than per pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumMode {
+    /// Compute the checksum in software (safe everywhere; the default).
+    #[default]
+    Compute,
+    /// Leave the checksum zeroed, assuming the NIC fills it in.
+    Offload,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChecksumCapabilities {
+    pub icmpv6: ChecksumMode,
+}
+
+impl ChecksumCapabilities {
+    pub fn compute() -> Self {
+        Self { icmpv6: ChecksumMode::Compute }
+    }
+
+    pub fn offload() -> Self {
+        Self { icmpv6: ChecksumMode::Offload }
+    }
+}
+
+/// Byte offset of the ICMPv6 checksum field within a packet built by [`make_icmpv6_packet`].
+pub fn icmpv6_checksum_offset(has_ethernet_header: bool) -> usize {
+    let link_header_len = if has_ethernet_header { 14 } else { 0 };
+    link_header_len + 40 + 2
+}
+
 const IPV6PREFIX:[u16;4] = [0x2001, 0x610, 0x1908, 0xa000];
 
 pub fn to_addr(pos: Pos, color: Color) -> Ipv6Addr {
@@ -55,16 +93,28 @@ pub fn to_addr(pos: Pos, color: Color) -> Ipv6Addr {
     )
 }
 
+/// The inverse of [`to_addr`]: recovers the position and color encoded into a painting address.
+pub fn from_addr(addr: Ipv6Addr) -> (Pos, Color) {
+    let s = addr.segments();
+    let pos = Pos::new(s[4], s[5]);
+    let color = Color::new_alpha((s[7] >> 8) as u8, (s[6] & 0xFF) as u8, (s[6] >> 8) as u8, (s[7] & 0xFF) as u8);
+    (pos, color)
+}
+
 // https://datatracker.ietf.org/doc/html/rfc1071
 pub fn icmpv6_checksum(src_ip: Ipv6Addr, dest_ip: Ipv6Addr, icmpv6_packet: &[u8]) -> u16 {
     let mut data = make_ipv6_pseudo_header(src_ip, dest_ip, icmpv6_packet.len() as u16);
     icmpv6_packet.iter().for_each(|byte| data.push(*byte));
 
+    !fold_checksum(sum_words(&data))
+}
+
+/// Sums `data` as 16-bit words (padding a trailing odd byte with zero), per RFC 1071.
+fn sum_words(data: &[u8]) -> u32 {
     let mut total: u32 = 0;
     let mut i = 0;
     let mut words = (data.len() + 1) / 2;
 
-    // Iterate over 16-bit words
     loop {
         if words <= 0 {
             break;
@@ -81,11 +131,114 @@ pub fn icmpv6_checksum(src_ip: Ipv6Addr, dest_ip: Ipv6Addr, icmpv6_packet: &[u8]
         i += 2;
     }
 
+    total
+}
+
+/// Ones-complement carry-around reduction of a 32-bit running sum down to 16 bits.
+fn fold_checksum(mut total: u32) -> u16 {
     while (total & 0xffff0000) > 0 {
         total = (total >> 16) + (total & 0xffff);
     }
+    total as u16
+}
+
+/// RFC 1624 incremental checksum update for one changed 16-bit word:
+/// `HC' = ~(~HC + ~m + m')`. Lets a single changed word (e.g. a recolored
+/// pixel) be folded into an existing checksum without re-summing the packet.
+pub fn incremental_checksum_update(old_checksum: u16, old_word: u16, new_word: u16) -> u16 {
+    let sum = (!old_checksum as u32) + (!old_word as u32) + (new_word as u32);
+    !fold_checksum(sum)
+}
 
-    return !(total as u16);
+/// Extracts the two color words of an address built by [`to_addr`], in the
+/// byte-pairing convention [`sum_words`] sums them in.
+pub fn color_words(addr: Ipv6Addr) -> [u16; 2] {
+    let o = addr.octets();
+    [
+        ((o[13] as u16) << 8) | o[12] as u16,
+        ((o[15] as u16) << 8) | o[14] as u16,
+    ]
+}
+
+/// A reusable packet buffer for the per-pixel hot loop. Everything in
+/// [`make_icmpv6_packet`]'s output except the destination address and the
+/// ICMPv6 checksum is constant across pixels (same source address, lengths,
+/// next-header and fixed 8-byte ICMP body), so it's computed once here and
+/// only the destination-address bytes and checksum are patched per pixel.
+pub struct PacketTemplate {
+    data: Vec<u8>,
+    dest_addr_offset: usize,
+    checksum_offset: usize,
+    base_sum: u32,
+    checksum_caps: ChecksumCapabilities,
+}
+
+impl PacketTemplate {
+    pub fn new(ethernet_info: Option<EthernetInfo>, src_ip: Ipv6Addr, checksum_caps: ChecksumCapabilities) -> Self {
+        let link_header_len = if ethernet_info.is_some() { 14 } else { 0 };
+        let dest_addr_offset = link_header_len + 24;
+        let icmpv6_header_offset = link_header_len + 40;
+        let checksum_offset = icmpv6_header_offset + 2;
+
+        let data = make_icmpv6_packet(ethernet_info, src_ip, Ipv6Addr::UNSPECIFIED, checksum_caps);
+
+        // base_sum = every word that stays constant across pixels: the
+        // pseudo header with the variable (x, y, color) half of the
+        // destination address zeroed out, plus the fixed ICMPv6 body.
+        let dest_with_prefix_only = to_addr(Pos::new(0, 0), Color::new(0, 0, 0));
+        let mut constant_bytes = make_ipv6_pseudo_header(src_ip, dest_with_prefix_only, 8);
+        // Bytes 16..32 of the pseudo header are the destination address; its
+        // variable (x, y, color) half is the last 8 of those 16 bytes.
+        constant_bytes[24..32].fill(0);
+        constant_bytes.extend_from_slice(&data[icmpv6_header_offset..icmpv6_header_offset + 8]);
+        // The checksum field itself must be zero while summing, as it was when the real checksum was computed.
+        let body_checksum_offset = constant_bytes.len() - 6;
+        constant_bytes[body_checksum_offset..body_checksum_offset + 2].fill(0);
+        let base_sum = sum_words(&constant_bytes);
+
+        Self {
+            data,
+            dest_addr_offset,
+            checksum_offset,
+            base_sum,
+            checksum_caps,
+        }
+    }
+
+    /// The checksum `pos`/`color` would produce, without patching `self.data`.
+    /// Always `0` when offloading, skipping the fold entirely.
+    pub fn checksum_for(&self, pos: Pos, color: Color) -> u16 {
+        if self.checksum_caps.icmpv6 == ChecksumMode::Offload {
+            return 0;
+        }
+
+        let dest_octets = to_addr(pos, color).octets();
+        let mut total = self.base_sum;
+        for variable_word in dest_octets[8..].chunks_exact(2) {
+            total += ((variable_word[1] as u32) << 8) | variable_word[0] as u32;
+        }
+        !fold_checksum(total)
+    }
+
+    /// Patches the destination address in place and sets `checksum` directly
+    /// (already known, e.g. from [`PacketTemplate::checksum_for`] or an
+    /// [`incremental_checksum_update`]), returning the finished packet. The
+    /// checksum field is left zeroed when offloading, regardless of `checksum`.
+    pub fn packet_with_checksum(&mut self, pos: Pos, color: Color, checksum: u16) -> &[u8] {
+        let dest_octets = to_addr(pos, color).octets();
+        self.data[self.dest_addr_offset..self.dest_addr_offset + 16].copy_from_slice(&dest_octets);
+        if self.checksum_caps.icmpv6 == ChecksumMode::Compute {
+            self.data[self.checksum_offset] = (checksum & 0xFF) as u8;
+            self.data[self.checksum_offset + 1] = (checksum >> 8) as u8;
+        }
+        &self.data
+    }
+
+    /// Patches the destination address and checksum in place for `pos`/`color`, returning the finished packet.
+    pub fn packet(&mut self, pos: Pos, color: Color) -> &[u8] {
+        let checksum = self.checksum_for(pos, color);
+        self.packet_with_checksum(pos, color, checksum)
+    }
 }
 
 pub fn make_ipv6_pseudo_header(
@@ -114,6 +267,7 @@ pub fn make_icmpv6_packet(
     ethernet_info: Option<EthernetInfo>,
     src_ip: Ipv6Addr,
     dest_ip: Ipv6Addr,
+    checksum_caps: ChecksumCapabilities,
 ) -> Vec<u8> {
     let mut data = Vec::new();
 
@@ -174,10 +328,44 @@ pub fn make_icmpv6_packet(
     // Ping Data...
     // <Empty> for now
 
-    // Calculate ICMPv6 Checksum...
-    let checksum = icmpv6_checksum(src_ip, dest_ip, &data[icmpv6_header_start_index..]);
-    data[icmpv6_checksum_index] = (checksum & 0xFF) as u8;
-    data[icmpv6_checksum_index + 1] = (checksum >> 8) as u8;
+    // Calculate ICMPv6 Checksum, unless the NIC is expected to offload it (left zeroed above).
+    if checksum_caps.icmpv6 == ChecksumMode::Compute {
+        let checksum = icmpv6_checksum(src_ip, dest_ip, &data[icmpv6_header_start_index..]);
+        data[icmpv6_checksum_index] = (checksum & 0xFF) as u8;
+        data[icmpv6_checksum_index + 1] = (checksum >> 8) as u8;
+    }
 
     data
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `PacketTemplate` is a byte-for-byte fast path for `make_icmpv6_packet`;
+    /// its precomputed `base_sum` must agree with a full checksum recompute
+    /// for every pixel, with and without an Ethernet header.
+    #[test]
+    fn packet_template_matches_make_icmpv6_packet() {
+        let src_ip: Ipv6Addr = "2001:610:1908:a000::1".parse().unwrap();
+        let ethernet_info = EthernetInfo::new(
+            "00:11:22:33:44:55".parse().unwrap(),
+            "aa:bb:cc:dd:ee:ff".parse().unwrap(),
+        );
+        let pixels = [
+            (Pos::new(0, 0), Color::new(0, 0, 0)),
+            (Pos::new(100, 200), Color::new(10, 20, 30)),
+            (Pos::new(1919, 1079), Color::new_alpha(255, 0, 128, 42)),
+        ];
+
+        for ethernet_info in [None, Some(ethernet_info)] {
+            let mut template = PacketTemplate::new(ethernet_info, src_ip, ChecksumCapabilities::compute());
+            for (pos, color) in pixels {
+                let expected =
+                    make_icmpv6_packet(ethernet_info, src_ip, to_addr(pos, color), ChecksumCapabilities::compute());
+                let actual = template.packet(pos, color);
+                assert_eq!(actual, expected.as_slice());
+            }
+        }
+    }
+}
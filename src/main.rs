@@ -8,19 +8,49 @@ use std::{
     path::PathBuf,
     sync::mpsc::{sync_channel, Receiver, SyncSender},
     thread,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use clap::{Parser, Subcommand};
 use color_eyre::eyre::{bail, eyre, Context};
 use color_eyre::Result;
 use mac_address::MacAddress;
+use place_ipv6::ndisc::{make_neighbor_solicitation, parse_neighbor_advertisement};
+use place_ipv6::pcap::{LinkType, PcapWriter};
+use place_ipv6::simulate::{Canvas, ConvergenceTracker, NetworkFaults};
 use place_ipv6::*;
 use rand::seq::SliceRandom;
 
 #[macro_use]
 extern crate log;
 
+/// CLI-facing mirror of `place_ipv6::ChecksumMode`, kept here like [`Medium`] so lib.rs stays free of a clap dependency.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ChecksumArg {
+    /// Compute the ICMPv6 checksum in software (the default; safe everywhere).
+    Compute,
+    /// Leave the ICMPv6 checksum zeroed, assuming the NIC fills it in. Taken on trust: the raw-socket send path can't observe whether the NIC actually does this.
+    Offload,
+}
+
+impl From<ChecksumArg> for ChecksumCapabilities {
+    fn from(arg: ChecksumArg) -> Self {
+        match arg {
+            ChecksumArg::Compute => ChecksumCapabilities::compute(),
+            ChecksumArg::Offload => ChecksumCapabilities::offload(),
+        }
+    }
+}
+
+/// Link-layer medium to frame packets for, mirroring smoltcp's Ethernet/IP medium split.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Medium {
+    /// Prepend a 14-byte Ethernet header (the default). Requires --dest-mac or --auto.
+    Ethernet,
+    /// Emit bare IPv6 packets with no link-layer header, for tun/point-to-point interfaces (WireGuard, 6in4, ...).
+    Ip,
+}
+
 #[derive(Subcommand, Clone)]
 enum Commands {
     /// Read a stream of raw rgb or rgba frames in 1920x1080 size (usually from ffmpeg with the flags `-pix_fmt rgb24/rgba -f rawvideo pipe:1`)
@@ -61,13 +91,19 @@ struct Args {
     /// What interface to send the packets on.
     #[arg(short = 'i', long)]
     iface_name: String,
-    /// The IPv6 assigned to the specified interface.
+    /// The IPv6 assigned to the specified interface. Auto-discovered from the interface if omitted and --auto is set.
     #[arg(short = 's', long)]
-    src_ip: Ipv6Addr,
-    /// The Mac of the next hop where the packet needs to go to/through.
+    src_ip: Option<Ipv6Addr>,
+    /// What link-layer medium to frame packets for.
+    #[arg(short = 'm', long, value_enum, default_value = "ethernet")]
+    medium: Medium,
+    /// The Mac of the next hop where the packet needs to go to/through. Required for --medium ethernet, unless --auto is set.
     /// Use e.g. `ip route get 2620:119:35::35` and `ip -6 neigh` to find it.
     #[arg(short = 'd', long)]
-    dest_mac: MacAddress,
+    dest_mac: Option<MacAddress>,
+    /// Auto-discover --src-ip (first global address on --iface-name) and --dest-mac (via Neighbor Discovery against the default gateway) instead of requiring them.
+    #[arg(long, action)]
+    auto: bool,
     /// If set, limit transmission speed to the given packets/sec.
     #[arg(short = 'r', long)]
     packets_per_sec: Option<u32>,
@@ -95,6 +131,231 @@ struct Args {
     /// Skip all pixels bigger than given value (at input resolution)
     #[arg(long, default_value = "9999")]
     max_y: u16,
+
+    /// Instead of (or in addition to, see --dry-run) sending packets on the wire, record them to this libpcap file for inspection in Wireshark or replay with tcpreplay
+    #[arg(long)]
+    pcap_out: Option<PathBuf>,
+    /// Don't actually send packets on the interface. Only useful together with --pcap-out
+    #[arg(long, action)]
+    dry_run: bool,
+
+    /// Simulate this fraction (0.0-1.0) of packets being dropped instead of actually sending, and report convergence against the source frame. Only honored by raw-pipe-stdin. See also --simulate-reorder and --simulate-dup.
+    #[arg(long, default_value = "0")]
+    simulate_loss: f64,
+    /// Simulate this fraction (0.0-1.0) of surviving packets being reordered instead of actually sending. Only honored by raw-pipe-stdin.
+    #[arg(long, default_value = "0")]
+    simulate_reorder: f64,
+    /// Simulate this fraction (0.0-1.0) of surviving packets being duplicated instead of actually sending. Only honored by raw-pipe-stdin.
+    #[arg(long, default_value = "0")]
+    simulate_dup: f64,
+
+    /// Whether to compute the ICMPv6 checksum in software or assume the NIC offloads it. `offload` is trusted, not verified: make sure your NIC actually does this.
+    #[arg(long, value_enum, default_value = "compute")]
+    checksum: ChecksumArg,
+}
+
+/// Opens a `PcapWriter` at `path` if requested, picking `LinkType::Ethernet`
+/// when the run has Ethernet framing and `LinkType::Raw` otherwise.
+fn open_pcap_writer(path: &Option<PathBuf>, ethernet_info: Option<EthernetInfo>) -> Result<Option<PcapWriter>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let link_type = if ethernet_info.is_some() {
+        LinkType::Ethernet
+    } else {
+        LinkType::Raw
+    };
+    Ok(Some(
+        PcapWriter::create(path, link_type).context("Creating pcap output file")?,
+    ))
+}
+
+/// Spacing between recorded pcap timestamps that reproduces `packets_per_sec`.
+fn pcap_spacing(packets_per_sec: Option<u32>) -> Duration {
+    match packets_per_sec {
+        Some(pps) if pps > 0 => Duration::from_secs_f64(1f64 / pps as f64),
+        _ => Duration::ZERO,
+    }
+}
+
+/// Builds the `EthernetInfo` for `medium` from an already-resolved `dest_mac`
+/// (see [`resolve_src_ip`] and [`resolve_dest_mac`]), or `None` for
+/// `Medium::Ip` (bare IPv6, suitable for a tun/point-to-point interface).
+/// Warns if the interface's actual data link type disagrees with `medium`.
+fn build_ethernet_info(
+    medium: Medium,
+    data_link: rawsock::DataLink,
+    src_mac: MacAddress,
+    dest_mac: Option<MacAddress>,
+) -> Result<Option<EthernetInfo>> {
+    let is_ethernet_iface = matches!(data_link, rawsock::DataLink::Ethernet);
+
+    match medium {
+        Medium::Ethernet => {
+            if !is_ethernet_iface {
+                warn!("--medium ethernet was requested, but the interface doesn't report an Ethernet data link. Continuing anyway.");
+            }
+            let dest_mac = dest_mac
+                .ok_or_else(|| eyre!("--dest-mac is required for --medium ethernet (or pass --auto)"))?;
+            Ok(Some(EthernetInfo::new(src_mac, dest_mac)))
+        }
+        Medium::Ip => {
+            if is_ethernet_iface {
+                warn!("--medium ip was requested on an Ethernet interface. No link header will be sent; make sure the interface expects bare IPv6 frames (e.g. a tun device).");
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Resolves the source IPv6 address: `args.src_ip` if given, otherwise (only
+/// with `--auto`) the first global-scope address configured on `iface_name`.
+fn resolve_src_ip(args: &Args, iface_name: &str) -> Result<Ipv6Addr> {
+    if let Some(src_ip) = args.src_ip {
+        return Ok(src_ip);
+    }
+    if !args.auto {
+        bail!("--src-ip is required unless --auto is set");
+    }
+
+    let output = std::process::Command::new("ip")
+        .args(["-6", "-o", "addr", "show", "dev", iface_name, "scope", "global"])
+        .output()
+        .context("Running `ip -6 addr show` to auto-discover a source address")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let src_ip: Ipv6Addr = stdout
+        .lines()
+        .find_map(|line| line.split_whitespace().nth(3))
+        .and_then(|cidr| cidr.split('/').next())
+        .ok_or_else(|| eyre!("No global IPv6 address found on interface {iface_name}"))?
+        .parse()
+        .context("Parsing auto-discovered source address")?;
+    info!("Auto-discovered source address: {src_ip}");
+    Ok(src_ip)
+}
+
+/// Finds the default IPv6 gateway for `iface_name` via `ip -6 route show default`.
+fn discover_gateway(iface_name: &str) -> Result<Ipv6Addr> {
+    let output = std::process::Command::new("ip")
+        .args(["-6", "route", "show", "default", "dev", iface_name])
+        .output()
+        .context("Running `ip -6 route show default` to auto-discover the gateway")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut fields = stdout.split_whitespace();
+    while let Some(field) = fields.next() {
+        if field == "via" {
+            if let Some(gateway) = fields.next() {
+                return gateway.parse().context("Parsing auto-discovered gateway address");
+            }
+        }
+    }
+    bail!("No default IPv6 route found on interface {iface_name}")
+}
+
+/// Reads an already-resolved neighbor cache entry for `target` from `ip -6 neigh show`, if any.
+fn neighbor_cache_lookup(iface_name: &str, target: Ipv6Addr) -> Option<MacAddress> {
+    let output = std::process::Command::new("ip")
+        .args(["-6", "neigh", "show", "dev", iface_name])
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next()? != target.to_string() {
+            return None;
+        }
+        if line.contains("FAILED") || line.contains("INCOMPLETE") {
+            return None;
+        }
+        let lladdr_index = line.find("lladdr")?;
+        line[lladdr_index + "lladdr".len()..]
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    })
+}
+
+/// Sends a Neighbor Solicitation and waits up to 5s for a matching Neighbor
+/// Advertisement, via a single `transceive` callback: `Some(data)` sends
+/// `data`, `None` polls for one received frame. A single callback (rather
+/// than separate send/receive ones) is required here because the two
+/// operations borrow the caller's interface handle differently (send needs
+/// only shared access, receive needs unique access) and two closures over
+/// the same handle would both need to be alive at once; one callback keeps
+/// this agnostic to which `rawsock` interface type the caller has open
+/// while only ever borrowing it for the duration of one call.
+fn resolve_neighbor_mac(
+    src_ip: Ipv6Addr,
+    src_mac: MacAddress,
+    target: Ipv6Addr,
+    mut transceive: impl FnMut(Option<&[u8]>) -> Result<Option<Vec<u8>>>,
+) -> Result<MacAddress> {
+    let solicitation = make_neighbor_solicitation(Some(src_mac), src_ip, target);
+    transceive(Some(&solicitation)).context("Sending Neighbor Solicitation")?;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if let Some(frame) = transceive(None)? {
+            if let Some(mac) = parse_neighbor_advertisement(&frame, true, target) {
+                return Ok(mac);
+            }
+        }
+    }
+
+    bail!("Timed out waiting for a Neighbor Advertisement from {target}")
+}
+
+/// Resolves the next-hop MAC for `args.medium`: `args.dest_mac` if given,
+/// `None` for `Medium::Ip`, otherwise (only with `--auto`) an existing
+/// neighbor cache entry or a fresh Neighbor Solicitation against the default gateway.
+fn resolve_dest_mac(
+    args: &Args,
+    iface_name: &str,
+    src_ip: Ipv6Addr,
+    src_mac: MacAddress,
+    transceive: impl FnMut(Option<&[u8]>) -> Result<Option<Vec<u8>>>,
+) -> Result<Option<MacAddress>> {
+    if args.medium == Medium::Ip {
+        return Ok(None);
+    }
+    if let Some(dest_mac) = args.dest_mac {
+        return Ok(Some(dest_mac));
+    }
+    if !args.auto {
+        bail!("--dest-mac is required for --medium ethernet unless --auto is set");
+    }
+
+    let gateway = discover_gateway(iface_name)?;
+    info!("Auto-discovered gateway: {gateway}");
+
+    let mac = match neighbor_cache_lookup(iface_name, gateway) {
+        Some(mac) => {
+            info!("Using cached neighbor entry for {gateway}: {mac}");
+            mac
+        }
+        None => {
+            info!("No cached neighbor entry for {gateway}. Sending Neighbor Solicitation...");
+            resolve_neighbor_mac(src_ip, src_mac, gateway, transceive)?
+        }
+    };
+    info!("Resolved {gateway} to {mac}");
+    Ok(Some(mac))
+}
+
+/// Resolves `args.checksum` to actual `ChecksumCapabilities`. `offload` is
+/// taken on trust, not verified: we capture packets at the raw-socket layer,
+/// which sits *above* where NIC checksum offload is applied, so there's no
+/// way to observe the NIC-corrected checksum from here to confirm it's
+/// actually happening. Logs a warning so the user knows this is on them.
+fn resolve_checksum_caps(args: &Args) -> ChecksumCapabilities {
+    match args.checksum {
+        ChecksumArg::Compute => ChecksumCapabilities::compute(),
+        ChecksumArg::Offload => {
+            warn!("--checksum offload is trusted, not verified: this tool can't observe NIC checksum offload from the raw-socket layer it sends from. Make sure your NIC actually offloads ICMPv6 checksums, or pings will be silently dropped by receivers.");
+            ChecksumCapabilities::offload()
+        }
+    }
 }
 
 fn main() -> Result<()> {
@@ -149,26 +410,50 @@ fn run_rawpipe_stdin(args: Args, resend_same_pixel_max: usize, width: u16, heigh
         let mut counter: u64 = 0;
         let iface_name = &args.iface_name;
         let lib = rawsock::open_best_library().unwrap();
-        let iface = lib.open_interface(iface_name).unwrap();
+        let mut iface = lib.open_interface(iface_name).unwrap();
         iface.break_loop();
 
         let src_mac = mac_address::mac_address_by_name(iface_name)
             .unwrap()
             .ok_or(eyre!("No mac :("))
             .unwrap();
-        let dest_mac = args.dest_mac;
         info!("RX: Src Mac: {}", src_mac);
-        let ethernet_info = match iface.data_link() {
-            rawsock::DataLink::Ethernet => Some(EthernetInfo::new(src_mac, dest_mac)),
-            _ => None,
-        };
+
+        let src_ip = resolve_src_ip(&args, iface_name).unwrap();
+        let dest_mac = resolve_dest_mac(&args, iface_name, src_ip, src_mac, |op| match op {
+            Some(data) => iface.send(data).map(|_| None).map_err(|error| eyre!("{error}")),
+            None => Ok(iface.receive().ok().map(|packet| packet.to_vec())),
+        })
+        .unwrap();
+        let ethernet_info = build_ethernet_info(args.medium, iface.data_link(), src_mac, dest_mac).unwrap();
+
+        let checksum_caps = resolve_checksum_caps(&args);
+
+        let mut pcap_writer = open_pcap_writer(&args.pcap_out, ethernet_info).unwrap();
+        let pcap_spacing = pcap_spacing(args.packets_per_sec);
+
+        let mut template = PacketTemplate::new(ethernet_info, src_ip, checksum_caps);
+        let mut pixel_cache: Vec<Option<(Color, u16)>> =
+            vec![None; width as usize * height as usize];
 
         let mut packet_counter;
         info!("RX: Ready...");
-        let src_ip = args.src_ip;
         let mut last_sec = Instant::now();
         let mut last_sec_counter = 0;
 
+        let faults = NetworkFaults {
+            loss_probability: args.simulate_loss,
+            reorder_probability: args.simulate_reorder,
+            dup_probability: args.simulate_dup,
+        };
+        let mut simulated_pass_counter: u64 = 0;
+        // Packets a prior pass reordered into arriving late; delivered after
+        // this pass's own packets so the delay is actually observable (see
+        // `NetworkFaults::apply`).
+        let mut held_over: Vec<Vec<u8>> = Vec::new();
+        let mut reconstructed_canvas = Canvas::new(args.offset_x + width, args.offset_y + height);
+        let mut convergence = ConvergenceTracker::new(args.offset_x + width, args.offset_y + height);
+
         let mut last_frames: VecDeque<Vec<u8>> =
             VecDeque::with_capacity(resend_same_pixel_max);
         let color_at = |buf: &[u8], x: u16, y: u16| {
@@ -214,8 +499,28 @@ fn run_rawpipe_stdin(args: Args, resend_same_pixel_max: usize, width: u16, heigh
                 }
 
                 if send {
-                    let dest_addr = to_addr(Pos::new(args.offset_x + x, args.offset_y + y), color);
-                    let data = make_icmpv6_packet(ethernet_info, src_ip, dest_addr);
+                    let pos = Pos::new(args.offset_x + x, args.offset_y + y);
+                    let cache_index = y as usize * width as usize + x as usize;
+
+                    let checksum = if checksum_caps.icmpv6 == ChecksumMode::Offload {
+                        0
+                    } else {
+                        match pixel_cache[cache_index] {
+                            Some((old_color, old_checksum)) if resend_same_pixel_max > 0 => {
+                                let [old_word1, old_word2] = color_words(to_addr(pos, old_color));
+                                let [new_word1, new_word2] = color_words(to_addr(pos, color));
+                                let checksum =
+                                    incremental_checksum_update(old_checksum, old_word1, new_word1);
+                                incremental_checksum_update(checksum, old_word2, new_word2)
+                            }
+                            _ => template.checksum_for(pos, color),
+                        }
+                    };
+
+                    let data = template.packet_with_checksum(pos, color, checksum).to_vec();
+                    if resend_same_pixel_max > 0 {
+                        pixel_cache[cache_index] = Some((color, checksum));
+                    }
                     data_array.push(data);
                     packet_counter += 1;
                 }
@@ -227,6 +532,21 @@ fn run_rawpipe_stdin(args: Args, resend_same_pixel_max: usize, width: u16, heigh
                 }
             }
 
+            // Ground truth for convergence reporting below: every in-bounds
+            // pixel the source frame actually intends, independent of
+            // whatever `--resend-same-pixel-max` filtered out of `data_array`.
+            let mut intended_canvas = Canvas::new(args.offset_x + width, args.offset_y + height);
+            if faults.is_active() {
+                for py in 0..height {
+                    for px in 0..width {
+                        if px < args.min_x || px > args.max_x || py < args.min_y || py > args.max_y {
+                            continue;
+                        }
+                        intended_canvas.set_pixel(Pos::new(args.offset_x + px, args.offset_y + py), color_at(&buffer, px, py));
+                    }
+                }
+            }
+
             if resend_same_pixel_max > 0 {
                 while last_frames.len() >= resend_same_pixel_max {
                     last_frames.pop_front();
@@ -239,6 +559,41 @@ fn run_rawpipe_stdin(args: Args, resend_same_pixel_max: usize, width: u16, heigh
                 data_array.shuffle(&mut rng);
             }
 
+            if faults.is_active() {
+                simulated_pass_counter += 1;
+                let built_packet_count = data_array.len();
+
+                let (deliver_now, next_held_over) = faults.apply(&mut rng, data_array);
+                let mut delivered = deliver_now;
+                delivered.extend(held_over);
+                held_over = next_held_over;
+
+                for data in &delivered {
+                    reconstructed_canvas.apply_packet(data, ethernet_info.is_some());
+                }
+
+                let newly_converged = convergence.record_pass(simulated_pass_counter, &intended_canvas, &reconstructed_canvas);
+                let avg_passes_to_converge = if newly_converged.is_empty() {
+                    0.0
+                } else {
+                    newly_converged.iter().sum::<u64>() as f64 / newly_converged.len() as f64
+                };
+
+                info!(
+                    "RX: [simulate] pass {}: {}/{} painted pixels converged ({} pixels newly converged this pass, avg {:.1} passes; {} pending; {} of {} packets delivered this pass)",
+                    simulated_pass_counter,
+                    reconstructed_canvas.correct_pixel_count(&intended_canvas),
+                    intended_canvas.painted_pixel_count(),
+                    newly_converged.len(),
+                    avg_passes_to_converge,
+                    convergence.pending_count(),
+                    delivered.len(),
+                    built_packet_count,
+                );
+                counter += 1;
+                continue;
+            }
+
             info!("RX: Sending frame as {} pings...", data_array.len());
             for data in data_array {
                 if let Some(ref packets_per_sec) = args.packets_per_sec {
@@ -253,7 +608,12 @@ fn run_rawpipe_stdin(args: Args, resend_same_pixel_max: usize, width: u16, heigh
                         }
                     }
                 }
-                iface.send(&data).unwrap();
+                if let Some(ref mut pcap_writer) = pcap_writer {
+                    pcap_writer.write_packet(&data, pcap_spacing).unwrap();
+                }
+                if !args.dry_run {
+                    iface.send(&data).unwrap();
+                }
                 packet_counter += 1;
             }
             //iface.send(&all_data).unwrap();
@@ -311,19 +671,26 @@ fn run_image(
 ) -> Result<()> {
     let iface_name = &args.iface_name;
     let lib = rawsock::open_best_library().unwrap();
-    let iface = lib.open_interface(iface_name).unwrap();
+    let mut iface = lib.open_interface(iface_name).unwrap();
     iface.break_loop();
 
     let src_mac = mac_address::mac_address_by_name(iface_name)
         .unwrap()
         .ok_or(eyre!("No mac :("))
         .unwrap();
-    let dest_mac = args.dest_mac;
     info!("Src Mac: {}", src_mac);
-    let ethernet_info = match iface.data_link() {
-        rawsock::DataLink::Ethernet => Some(EthernetInfo::new(src_mac, dest_mac)),
-        _ => None,
-    };
+
+    let src_ip = resolve_src_ip(&args, iface_name)?;
+    let dest_mac = resolve_dest_mac(&args, iface_name, src_ip, src_mac, |op| match op {
+        Some(data) => iface.send(data).map(|_| None).map_err(|error| eyre!("{error}")),
+        None => Ok(iface.receive().ok().map(|packet| packet.to_vec())),
+    })?;
+    let ethernet_info = build_ethernet_info(args.medium, iface.data_link(), src_mac, dest_mac)?;
+
+    let checksum_caps = resolve_checksum_caps(&args);
+
+    let mut pcap_writer = open_pcap_writer(&args.pcap_out, ethernet_info)?;
+    let pcap_spacing = pcap_spacing(args.packets_per_sec);
 
     let mut rng: rand::rngs::ThreadRng = rand::thread_rng();
 
@@ -340,6 +707,7 @@ fn run_image(
     info!("Processing image...");
     let (mut worst_x_clip, mut worst_y_clip) = (0, 0);
     let mut data_array = Vec::<Vec<u8>>::with_capacity(img.width() as usize * img.height() as usize);
+    let mut template = PacketTemplate::new(ethernet_info, src_ip, checksum_caps);
     for (x, y, pixel) in img.to_rgba8().enumerate_pixels() {
         if let Some(alpha_treshold) = alpha_treshold {
             if pixel.0[3] < alpha_treshold {
@@ -362,11 +730,9 @@ fn run_image(
             continue; // Outside area. Skip
         }
 
-        let dest_ip = to_addr(
-            Pos::new(x_adj, y_adj),
-            Color::new_alpha(pixel.0[0], pixel.0[1], pixel.0[2], pixel.0[3]),
-        );
-        data_array.push(make_icmpv6_packet(ethernet_info, args.src_ip, dest_ip));
+        let pos = Pos::new(x_adj, y_adj);
+        let color = Color::new_alpha(pixel.0[0], pixel.0[1], pixel.0[2], pixel.0[3]);
+        data_array.push(template.packet(pos, color).to_vec());
     }
 
     if worst_x_clip > 0 || worst_y_clip > 0 {
@@ -399,7 +765,12 @@ fn run_image(
                     }
                 }
             }
-            iface.send(data).unwrap();
+            if let Some(ref mut pcap_writer) = pcap_writer {
+                pcap_writer.write_packet(data, pcap_spacing).unwrap();
+            }
+            if !args.dry_run {
+                iface.send(data).unwrap();
+            }
             packet_counter += 1;
         }
         iface.flush();
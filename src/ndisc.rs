@@ -0,0 +1,127 @@
+//! Minimal IPv6 Neighbor Discovery (RFC 4861) building blocks: just enough to
+//! solicit a neighbor's link-layer address and parse the advertisement that
+//! answers it, used by `--auto` to resolve `--dest-mac` without the user
+//! having to run `ip -6 neigh` by hand.
+
+use mac_address::MacAddress;
+use std::net::Ipv6Addr;
+
+use crate::icmpv6_checksum;
+
+/// The solicited-node multicast address for `target` (RFC 4291 §2.7.1):
+/// `ff02::1:ffXX:XXXX`, where `XX:XXXX` are the low 24 bits of `target`.
+pub fn solicited_node_multicast_addr(target: Ipv6Addr) -> Ipv6Addr {
+    let o = target.octets();
+    Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | o[13] as u16,
+        ((o[14] as u16) << 8) | o[15] as u16,
+    )
+}
+
+/// The Ethernet multicast MAC corresponding to [`solicited_node_multicast_addr`]: `33:33:ff:XX:XX:XX`.
+pub fn solicited_node_multicast_mac(target: Ipv6Addr) -> MacAddress {
+    let o = target.octets();
+    MacAddress::new([0x33, 0x33, 0xff, o[13], o[14], o[15]])
+}
+
+/// Builds a Neighbor Solicitation asking who owns `target_ip`. When
+/// `src_mac` is given, an Ethernet header addressed to `target_ip`'s
+/// solicited-node multicast MAC is prepended; otherwise the packet is bare
+/// IPv6, for tun/point-to-point mediums.
+pub fn make_neighbor_solicitation(src_mac: Option<MacAddress>, src_ip: Ipv6Addr, target_ip: Ipv6Addr) -> Vec<u8> {
+    let mut data = Vec::new();
+    let dest_ip = solicited_node_multicast_addr(target_ip);
+
+    if let Some(src_mac) = src_mac {
+        let dest_mac = solicited_node_multicast_mac(target_ip);
+        dest_mac.bytes().into_iter().for_each(|byte| data.push(byte));
+        src_mac.bytes().into_iter().for_each(|byte| data.push(byte));
+        let nextheader_type: u16 = 0x86dd; // IPv6
+        data.push((nextheader_type >> 8) as u8);
+        data.push((nextheader_type & 0xFF) as u8);
+    }
+
+    // IPv6 Header
+    data.push(0x60); // Version 6
+    data.push(0x00);
+    data.push(0x00);
+    data.push(0x00);
+
+    let payload_length: u16 = 24 /* NS header incl. target address */
+        + if src_mac.is_some() { 8 /* Source Link-Layer Address option */ } else { 0 };
+    data.push((payload_length >> 8) as u8);
+    data.push((payload_length & 0xFF) as u8);
+
+    data.push(0x3a); // Next header: ICMPv6 (58)
+    data.push(255); // Hop limit must be 255 for NDISC packets (RFC 4861 §4.3)
+
+    src_ip.octets().into_iter().for_each(|byte| data.push(byte));
+    dest_ip.octets().into_iter().for_each(|byte| data.push(byte));
+
+    // ICMPv6 Neighbor Solicitation
+    let icmpv6_start = data.len();
+    data.push(135); // Type: Neighbor Solicitation
+    data.push(0); // Code
+
+    let checksum_index = data.len();
+    data.push(0x00);
+    data.push(0x00);
+
+    data.extend_from_slice(&[0, 0, 0, 0]); // Reserved
+    target_ip.octets().into_iter().for_each(|byte| data.push(byte));
+
+    if let Some(src_mac) = src_mac {
+        data.push(1); // Option type: Source Link-Layer Address
+        data.push(1); // Length, in units of 8 octets
+        src_mac.bytes().into_iter().for_each(|byte| data.push(byte));
+    }
+
+    let checksum = icmpv6_checksum(src_ip, dest_ip, &data[icmpv6_start..]);
+    data[checksum_index] = (checksum & 0xFF) as u8;
+    data[checksum_index + 1] = (checksum >> 8) as u8;
+
+    data
+}
+
+/// Scans `frame` for a Neighbor Advertisement claiming `target_ip`, returning
+/// its advertised link-layer address (the Target Link-Layer Address option)
+/// if present. `has_ethernet_header` must match how `frame` was captured.
+pub fn parse_neighbor_advertisement(frame: &[u8], has_ethernet_header: bool, target_ip: Ipv6Addr) -> Option<MacAddress> {
+    let ip_start = if has_ethernet_header { 14 } else { 0 };
+    if frame.len() < ip_start + 40 || frame[ip_start] >> 4 != 6 || frame[ip_start + 6] != 0x3a {
+        return None; // Too short, not IPv6, or not ICMPv6
+    }
+
+    let icmpv6_start = ip_start + 40;
+    if frame.len() < icmpv6_start + 24 || frame[icmpv6_start] != 136 {
+        return None; // Not a Neighbor Advertisement
+    }
+
+    let advertised_target = Ipv6Addr::from(<[u8; 16]>::try_from(&frame[icmpv6_start + 8..icmpv6_start + 24]).ok()?);
+    if advertised_target != target_ip {
+        return None;
+    }
+
+    let mut option_start = icmpv6_start + 24;
+    while option_start + 2 <= frame.len() {
+        let option_type = frame[option_start];
+        let option_len_words = frame[option_start + 1] as usize;
+        if option_len_words == 0 || option_start + option_len_words * 8 > frame.len() {
+            break; // Malformed option length
+        }
+        if option_type == 2 && option_len_words * 8 >= 8 {
+            // Target Link-Layer Address
+            let mac_bytes: [u8; 6] = frame[option_start + 2..option_start + 8].try_into().ok()?;
+            return Some(MacAddress::new(mac_bytes));
+        }
+        option_start += option_len_words * 8;
+    }
+
+    None
+}
@@ -0,0 +1,73 @@
+//! Minimal classic (non-ng) libpcap writer.
+//!
+//! Used by `--pcap-out` to record generated frames to disk instead of (or in
+//! addition to) sending them on the wire, so they can be inspected in
+//! Wireshark or replayed later with `tcpreplay`.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// pcap linktype values we can emit. See https://www.tcpdump.org/linktypes.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkType {
+    /// LINKTYPE_ETHERNET: frames are prefixed with a 14-byte Ethernet header.
+    Ethernet,
+    /// LINKTYPE_RAW: frames are bare IPv6 packets with no link-layer header.
+    Raw,
+}
+
+impl LinkType {
+    fn as_u32(self) -> u32 {
+        match self {
+            LinkType::Ethernet => 1,
+            LinkType::Raw => 101,
+        }
+    }
+}
+
+/// Writes frames as a classic pcap file (global header + per-packet records).
+pub struct PcapWriter {
+    file: File,
+    elapsed: Duration,
+}
+
+impl PcapWriter {
+    /// Creates `path`, truncating it if it already exists, and writes the
+    /// global pcap header for `link_type`.
+    pub fn create(path: &Path, link_type: LinkType) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+
+        file.write_all(&0xa1b2c3d4u32.to_le_bytes())?; // magic number
+        file.write_all(&2u16.to_le_bytes())?; // version major
+        file.write_all(&4u16.to_le_bytes())?; // version minor
+        file.write_all(&0i32.to_le_bytes())?; // thiszone (GMT)
+        file.write_all(&0u32.to_le_bytes())?; // sigfigs
+        file.write_all(&65535u32.to_le_bytes())?; // snaplen
+        file.write_all(&link_type.as_u32().to_le_bytes())?; // network (linktype)
+
+        Ok(Self {
+            file,
+            elapsed: Duration::ZERO,
+        })
+    }
+
+    /// Appends one frame at the current recorded timestamp, then advances
+    /// that timestamp by `spacing` so a capture played back at its native
+    /// speed reproduces the `--packets-per-sec` rate it was recorded at.
+    pub fn write_packet(&mut self, data: &[u8], spacing: Duration) -> io::Result<()> {
+        let secs = self.elapsed.as_secs() as u32;
+        let usecs = self.elapsed.subsec_micros();
+        let len = data.len() as u32;
+
+        self.file.write_all(&secs.to_le_bytes())?;
+        self.file.write_all(&usecs.to_le_bytes())?;
+        self.file.write_all(&len.to_le_bytes())?; // captured length
+        self.file.write_all(&len.to_le_bytes())?; // original length
+        self.file.write_all(data)?;
+
+        self.elapsed += spacing;
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! Local loss/reorder/duplicate injection, inspired by smoltcp's fault
+//! injector, plus the canvas-reconstruction and convergence-tracking math
+//! needed to measure how well a given fault rate recovers under
+//! `--resend-same-pixel-max`. Nothing here touches the wire; it's a local
+//! convergence test for tuning resend strategy against an expected loss rate
+//! before actually deploying.
+
+use rand::Rng;
+use std::net::Ipv6Addr;
+
+use crate::{from_addr, Color, Pos};
+
+/// Bernoulli drop/duplicate and delayed (out-of-order) delivery applied to an
+/// already-built batch of packets, standing in for a lossy/reordering network.
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkFaults {
+    pub loss_probability: f64,
+    pub reorder_probability: f64,
+    pub dup_probability: f64,
+}
+
+impl NetworkFaults {
+    pub fn is_active(&self) -> bool {
+        self.loss_probability > 0.0 || self.reorder_probability > 0.0 || self.dup_probability > 0.0
+    }
+
+    /// Applies drop, duplicate, and delayed delivery to `packets`. Returns
+    /// `(deliver_now, hold_for_next_pass)`: `deliver_now` should be applied to
+    /// the receiver's canvas for this pass, in order; `hold_for_next_pass`
+    /// must be appended after the *next* pass's own packets before that pass
+    /// is delivered, so a packet reordered this pass can actually arrive late
+    /// enough to stomp a fresher update for the same pixel. That's what makes
+    /// out-of-order delivery observable at all, since within one pass every
+    /// packet already targets a distinct pixel and application order alone
+    /// wouldn't change anything.
+    pub fn apply(&self, rng: &mut impl Rng, packets: Vec<Vec<u8>>) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let mut deliver_now = Vec::with_capacity(packets.len());
+        let mut hold_for_next_pass = Vec::new();
+        for packet in packets {
+            if rng.gen_bool(self.loss_probability) {
+                continue; // Dropped
+            }
+            if rng.gen_bool(self.dup_probability) {
+                deliver_now.push(packet.clone());
+            }
+            if rng.gen_bool(self.reorder_probability) {
+                hold_for_next_pass.push(packet); // Delayed, arrives after this pass's other packets
+            } else {
+                deliver_now.push(packet);
+            }
+        }
+        (deliver_now, hold_for_next_pass)
+    }
+}
+
+/// Extracts the destination address from a packet built by `make_icmpv6_packet`.
+pub fn dest_ip_of_packet(data: &[u8], has_ethernet_header: bool) -> Option<Ipv6Addr> {
+    let ip_start = if has_ethernet_header { 14 } else { 0 };
+    let dest_start = ip_start + 24;
+    let bytes: [u8; 16] = data.get(dest_start..dest_start + 16)?.try_into().ok()?;
+    Some(Ipv6Addr::from(bytes))
+}
+
+/// A canvas reconstructed from the destination addresses of surviving
+/// packets (the inverse of [`crate::to_addr`]), rather than rendered
+/// directly, so it reflects exactly what a real painting receiver would see.
+pub struct Canvas {
+    width: u16,
+    height: u16,
+    pixels: Vec<Option<Color>>,
+}
+
+impl Canvas {
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![None; width as usize * height as usize],
+        }
+    }
+
+    fn index(&self, pos: Pos) -> Option<usize> {
+        if pos.x >= self.width || pos.y >= self.height {
+            return None;
+        }
+        Some(pos.y as usize * self.width as usize + pos.x as usize)
+    }
+
+    /// Decodes a packet's destination address and paints the resulting pixel.
+    pub fn apply_packet(&mut self, data: &[u8], has_ethernet_header: bool) {
+        let Some(dest_ip) = dest_ip_of_packet(data, has_ethernet_header) else {
+            return;
+        };
+        let (pos, color) = from_addr(dest_ip);
+        self.set_pixel(pos, color);
+    }
+
+    /// Directly paints `pos`, bypassing packet decoding. Used to build the
+    /// ground-truth canvas straight from the source frame buffer.
+    pub fn set_pixel(&mut self, pos: Pos, color: Color) {
+        if let Some(index) = self.index(pos) {
+            self.pixels[index] = Some(color);
+        }
+    }
+
+    pub fn pixel_at(&self, pos: Pos) -> Option<Color> {
+        self.index(pos).and_then(|index| self.pixels[index])
+    }
+
+    /// Counts pixels that `reference` actually painted and that `self` got
+    /// right. Cells `reference` never painted (`None`) don't count as
+    /// "correct", or every sparse/partial frame would look artificially converged.
+    pub fn correct_pixel_count(&self, reference: &Canvas) -> usize {
+        self.pixels
+            .iter()
+            .zip(reference.pixels.iter())
+            .filter(|(got, want)| want.is_some() && got == want)
+            .count()
+    }
+
+    pub fn painted_pixel_count(&self) -> usize {
+        self.pixels.iter().filter(|pixel| pixel.is_some()).count()
+    }
+
+    pub fn total_pixels(&self) -> usize {
+        self.pixels.len()
+    }
+}
+
+/// Tracks, per pixel, how many simulated passes (frames/resends) elapse
+/// between a pixel's intended color changing and the reconstructed canvas
+/// catching up to it, so `--resend-same-pixel-max` can be tuned against a
+/// given loss rate before deploying.
+pub struct ConvergenceTracker {
+    width: u16,
+    height: u16,
+    /// Pass index at which the currently-intended color started being sent, per pixel.
+    pending_since: Vec<Option<u64>>,
+    last_intended: Vec<Option<Color>>,
+}
+
+impl ConvergenceTracker {
+    pub fn new(width: u16, height: u16) -> Self {
+        let len = width as usize * height as usize;
+        Self {
+            width,
+            height,
+            pending_since: vec![None; len],
+            last_intended: vec![None; len],
+        }
+    }
+
+    fn index(&self, pos: Pos) -> Option<usize> {
+        if pos.x >= self.width || pos.y >= self.height {
+            return None;
+        }
+        Some(pos.y as usize * self.width as usize + pos.x as usize)
+    }
+
+    /// Call once per simulated pass with `pass` (this pass's 1-based index),
+    /// the ground-truth `intended` canvas, and the `received` canvas
+    /// reconstructed from surviving packets so far. Returns the number of
+    /// passes each pixel that newly converged this call took to get there.
+    pub fn record_pass(&mut self, pass: u64, intended: &Canvas, received: &Canvas) -> Vec<u64> {
+        let mut newly_converged = Vec::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let pos = Pos::new(x, y);
+                let Some(index) = self.index(pos) else { continue };
+                let intended_color = intended.pixel_at(pos);
+
+                if intended_color != self.last_intended[index] {
+                    self.last_intended[index] = intended_color;
+                    self.pending_since[index] = intended_color.map(|_| pass);
+                }
+
+                if let Some(since) = self.pending_since[index] {
+                    if intended_color.is_some() && received.pixel_at(pos) == intended_color {
+                        newly_converged.push(pass - since + 1);
+                        self.pending_since[index] = None;
+                    }
+                }
+            }
+        }
+        newly_converged
+    }
+
+    /// How many pixels are still waiting for their current intended color to arrive.
+    pub fn pending_count(&self) -> usize {
+        self.pending_since.iter().filter(|since| since.is_some()).count()
+    }
+}